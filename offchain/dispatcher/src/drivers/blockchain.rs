@@ -1,3 +1,4 @@
+use crate::drivers::wal::{WalEntry, WriteAheadLog};
 use crate::machine::BrokerReceive;
 use crate::tx_sender::TxSender;
 
@@ -5,34 +6,116 @@ use state_fold_types::ethereum_types::Address;
 use types::foldables::claims::History;
 
 use anyhow::Result;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
 
 use tracing::{info, instrument, trace};
 
 #[derive(Debug)]
 pub struct BlockchainDriver {
     dapp_address: Address,
+    // WAL I/O (replay/rewrite/append) runs synchronously on `react`'s async
+    // task rather than via `spawn_blocking`: entries are small and the
+    // common no-op tick only reads the log, without rewriting it (see
+    // `WriteAheadLog::retain`), so this is left as-is until the WAL grows
+    // large enough for that tradeoff to matter.
+    wal: Mutex<WriteAheadLog>,
 }
 
 impl BlockchainDriver {
-    pub fn new(dapp_address: Address) -> Self {
-        Self { dapp_address }
+    pub fn new(dapp_address: Address, wal_path: impl AsRef<Path>) -> Result<Self> {
+        let wal = WriteAheadLog::open(wal_path)?;
+        Ok(Self {
+            dapp_address,
+            wal: Mutex::new(wal),
+        })
     }
 
+    /// Reconciles the write-ahead log with the finalized and tip `History`
+    /// in a single replay pass: entries at or below `claims_finalized` are
+    /// confirmed beyond reorg risk, and entries above it whose hash no
+    /// longer matches the tip `history` were reorged out, so both kinds are
+    /// dropped from the WAL in one rewrite (`WriteAheadLog::retain` is a
+    /// no-op read when nothing needs dropping, e.g. on a steady-state tick
+    /// with no pruning to do). There is no separately persisted finalization
+    /// watermark: `claims_finalized` is recomputed from `finalized_history`
+    /// on every call. Everything else that survives is "pending" and is
+    /// returned keyed by claim number so `react` can match it against the
+    /// broker by hash instead of blindly resubmitting.
+    fn reconcile(
+        &self,
+        history: &History,
+        claims_finalized: u64,
+    ) -> Result<HashMap<u64, [u8; 32]>> {
+        let mut wal = self.wal.lock().expect("WAL mutex poisoned");
+        let remaining = wal.retain(|entry| {
+            if entry.number <= claims_finalized {
+                return false;
+            }
+            match history_claim_hash(history, &self.dapp_address, entry.number) {
+                Some(hash) if hash.as_bytes() != entry.hash => {
+                    trace!(
+                        "Claim `{}` reorged out of history, dropping from WAL",
+                        entry.number
+                    );
+                    false
+                }
+                _ => true,
+            }
+        })?;
+
+        Ok(remaining
+            .into_iter()
+            .map(|entry| (entry.number, entry.hash))
+            .collect())
+    }
+
+    /// `history` is the `History` at the current tip, used to detect claims
+    /// that were reorged out from under a pending submission and to skip
+    /// broker claims that already sit in the tip history.
+    /// `finalized_history` is the `History` as of the last finalized block;
+    /// a claim only counts as truly sent once it sits at or below that
+    /// height, so `finalized_history` is what gates resubmission.
     #[instrument(level = "trace", skip_all)]
     pub async fn react<TS: TxSender + Sync + Send>(
         &self,
+        current_block: u64,
         history: &History,
+        finalized_history: &History,
         broker: &impl BrokerReceive,
         mut tx_sender: TS,
     ) -> Result<TS> {
-        let claims_sent = claims_sent(history, &self.dapp_address);
-        trace!(?claims_sent);
+        let claims_finalized = claims_sent(finalized_history, &self.dapp_address);
+        trace!(?claims_finalized);
+
+        let mut pending = self.reconcile(history, claims_finalized)?;
 
         while let Some(claim) = broker.next_claim().await? {
             trace!("Got claim `{:?}` from broker", claim);
-            if claim.number > claims_sent {
+            if claim.number > claims_finalized {
+                if pending.get(&claim.number) == Some(&claim.hash) {
+                    trace!("Claim `{:?}` already pending, skipping", claim);
+                    continue;
+                }
+                if history_claim_hash(history, &self.dapp_address, claim.number)
+                    == Some(claim.hash.into())
+                {
+                    trace!("Claim `{:?}` already in tip history, skipping", claim);
+                    continue;
+                }
+
                 info!("Sending claim `{:?}`", claim);
+                {
+                    let mut wal = self.wal.lock().expect("WAL mutex poisoned");
+                    wal.append(&WalEntry {
+                        number: claim.number,
+                        hash: claim.hash,
+                        submitted_at_block: current_block,
+                    })?;
+                }
                 tx_sender = tx_sender.send_claim_tx(&claim.hash).await?;
+                pending.insert(claim.number, claim.hash);
             }
         }
 
@@ -47,6 +130,19 @@ fn claims_sent(history: &History, dapp_address: &Address) -> u64 {
     }
 }
 
+/// Looks up the hash of the claim `history` holds at position `number` (1-based,
+/// matching the convention of `claims_sent`/`RollupClaim::number`), so a pending
+/// WAL entry can be checked for a reorg without trusting the submission blindly.
+fn history_claim_hash(
+    history: &History,
+    dapp_address: &Address,
+    number: u64,
+) -> Option<state_fold_types::ethereum_types::H256> {
+    let claims = &history.dapp_claims.get(dapp_address)?.claims;
+    let index = usize::try_from(number.checked_sub(1)?).ok()?;
+    claims.get(index).map(|claim| claim.epoch_hash)
+}
+
 #[cfg(test)]
 mod tests {
     use im::{hashmap, Vector};
@@ -61,10 +157,17 @@ mod tests {
 
     // --------------------------------------------------------------------------------------------
 
+    fn new_driver(dapp_address: Address) -> (tempfile::TempDir, BlockchainDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let blockchain_driver =
+            BlockchainDriver::new(dapp_address, dir.path().join("wal.log")).unwrap();
+        (dir, blockchain_driver)
+    }
+
     #[test]
     fn test_new() {
         let dapp_address = H160::default();
-        let blockchain_driver = BlockchainDriver::new(dapp_address);
+        let (_dir, blockchain_driver) = new_driver(dapp_address);
         assert_eq!(blockchain_driver.dapp_address, dapp_address);
     }
 
@@ -94,11 +197,7 @@ mod tests {
         }
     }
 
-    fn update_history(
-        history: &History,
-        dapp_address: Address,
-        n: usize,
-    ) -> History {
+    fn update_history(history: &History, dapp_address: Address, n: usize) -> History {
         let claims = random_claims(n)
             .iter()
             .map(|x| Arc::new(x.clone()))
@@ -164,7 +263,7 @@ mod tests {
 
     async fn test_react(next_claims: Vec<u64>, n: usize) {
         let dapp_address = H160::random();
-        let blockchain_driver = BlockchainDriver::new(dapp_address);
+        let (_dir, blockchain_driver) = new_driver(dapp_address);
 
         let history = new_history();
         let history = update_history(&history, dapp_address, 5);
@@ -183,8 +282,9 @@ mod tests {
         let broker = mock::Broker::new(vec![], next_claims);
         let tx_sender = mock::TxSender::new();
 
-        let result =
-            blockchain_driver.react(&history, &broker, tx_sender).await;
+        let result = blockchain_driver
+            .react(1, &history, &history, &broker, tx_sender)
+            .await;
         assert!(result.is_ok());
         let tx_sender = result.unwrap();
         assert_eq!(tx_sender.count(), n);
@@ -218,4 +318,257 @@ mod tests {
     async fn test_react_interleaved_old_new_claims_sent_5_claims() {
         test_react(vec![1, 5, 6, 2, 3, 7, 8, 4, 5, 9, 10], 5).await;
     }
-}
\ No newline at end of file
+
+    // --------------------------------------------------------------------------------------------
+
+    // a claim already logged in the WAL as in-flight, with a matching hash,
+    // is not resubmitted
+    #[tokio::test]
+    async fn test_react_in_flight_claim_is_not_resent() {
+        let dapp_address = H160::random();
+        let (_dir, blockchain_driver) = new_driver(dapp_address);
+
+        let history = new_history();
+        let history = update_history(&history, dapp_address, 5);
+
+        let hash = [7u8; 32];
+        {
+            let mut wal = blockchain_driver.wal.lock().unwrap();
+            wal.append(&super::WalEntry {
+                number: 6,
+                hash,
+                submitted_at_block: 1,
+            })
+            .unwrap();
+        }
+
+        let next_claims = vec![RollupClaim { hash, number: 6 }];
+        let broker = mock::Broker::new(vec![], next_claims);
+        let tx_sender = mock::TxSender::new();
+
+        let result = blockchain_driver
+            .react(2, &history, &history, &broker, tx_sender)
+            .await;
+        assert!(result.is_ok());
+        let tx_sender = result.unwrap();
+        assert_eq!(tx_sender.count(), 0);
+    }
+
+    // a claim beyond claims_sent that is NOT logged in the WAL is sent and
+    // recorded
+    #[tokio::test]
+    async fn test_react_new_claim_is_logged_and_sent() {
+        let dapp_address = H160::random();
+        let (_dir, blockchain_driver) = new_driver(dapp_address);
+
+        let history = new_history();
+        let history = update_history(&history, dapp_address, 5);
+
+        let hash = [9u8; 32];
+        let next_claims = vec![RollupClaim { hash, number: 6 }];
+        let broker = mock::Broker::new(vec![], next_claims);
+        let tx_sender = mock::TxSender::new();
+
+        let result = blockchain_driver
+            .react(2, &history, &history, &broker, tx_sender)
+            .await;
+        assert!(result.is_ok());
+        let tx_sender = result.unwrap();
+        assert_eq!(tx_sender.count(), 1);
+
+        let wal = blockchain_driver.wal.lock().unwrap();
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].number, 6);
+        assert_eq!(entries[0].hash, hash);
+        assert_eq!(entries[0].submitted_at_block, 2);
+    }
+
+    // --------------------------------------------------------------------------------------------
+
+    // returns `history` with `n` random claims appended to `dapp_address`'s
+    // existing claims (unlike `update_history`, which replaces them)
+    fn extend_history(history: &History, dapp_address: Address, n: usize) -> History {
+        let mut claims = history
+            .dapp_claims
+            .get(&dapp_address)
+            .map(|c| c.claims.clone())
+            .unwrap_or_default();
+        for claim in random_claims(n) {
+            claims.push_back(Arc::new(claim));
+        }
+        let dapp_claims = history
+            .dapp_claims
+            .update(Arc::new(dapp_address), Arc::new(DAppClaims { claims }));
+        History {
+            history_address: history.history_address.clone(),
+            dapp_claims: Arc::new(dapp_claims),
+        }
+    }
+
+    // a pending claim (sent, but not yet finalized) is not resent as long as
+    // the finalized history hasn't caught up to it
+    #[tokio::test]
+    async fn test_react_pending_claim_not_yet_finalized_is_not_resent() {
+        let dapp_address = H160::random();
+        let (_dir, blockchain_driver) = new_driver(dapp_address);
+
+        let finalized_history = new_history();
+        let finalized_history = update_history(&finalized_history, dapp_address, 5);
+        let tip_history = extend_history(&finalized_history, dapp_address, 1);
+
+        let sent_hash = tip_history.dapp_claims.get(&dapp_address).unwrap().claims[5]
+            .epoch_hash
+            .to_fixed_bytes();
+        {
+            let mut wal = blockchain_driver.wal.lock().unwrap();
+            wal.append(&super::WalEntry {
+                number: 6,
+                hash: sent_hash,
+                submitted_at_block: 1,
+            })
+            .unwrap();
+        }
+
+        let next_claims = vec![RollupClaim {
+            hash: sent_hash,
+            number: 6,
+        }];
+        let broker = mock::Broker::new(vec![], next_claims);
+        let tx_sender = mock::TxSender::new();
+
+        let result = blockchain_driver
+            .react(2, &tip_history, &finalized_history, &broker, tx_sender)
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().count(), 0);
+
+        let wal = blockchain_driver.wal.lock().unwrap();
+        assert_eq!(wal.replay().unwrap().len(), 1);
+    }
+
+    // a claim that was pending but got reorged out of the tip history (a
+    // different claim now occupies its slot) is not treated as in-flight,
+    // so a matching broker claim is resent
+    #[tokio::test]
+    async fn test_react_reorged_claim_is_resent() {
+        let dapp_address = H160::random();
+        let (_dir, blockchain_driver) = new_driver(dapp_address);
+
+        let finalized_history = new_history();
+        let finalized_history = update_history(&finalized_history, dapp_address, 5);
+        // the tip now has a different claim #6 than the one we logged,
+        // simulating a reorg that replaced it
+        let tip_history = extend_history(&finalized_history, dapp_address, 1);
+
+        let reorged_hash = [42u8; 32];
+        {
+            let mut wal = blockchain_driver.wal.lock().unwrap();
+            wal.append(&super::WalEntry {
+                number: 6,
+                hash: reorged_hash,
+                submitted_at_block: 1,
+            })
+            .unwrap();
+        }
+
+        let next_claims = vec![RollupClaim {
+            hash: reorged_hash,
+            number: 6,
+        }];
+        let broker = mock::Broker::new(vec![], next_claims);
+        let tx_sender = mock::TxSender::new();
+
+        let result = blockchain_driver
+            .react(2, &tip_history, &finalized_history, &broker, tx_sender)
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().count(), 1);
+    }
+
+    // when the WAL holds two entries for the same claim number -- a stale
+    // one that was reorged out and a second one that still matches the tip
+    // history -- only the stale entry is pruned, not both
+    #[tokio::test]
+    async fn test_react_reconcile_prunes_only_the_reorged_entry() {
+        let dapp_address = H160::random();
+        let (_dir, blockchain_driver) = new_driver(dapp_address);
+
+        let finalized_history = new_history();
+        let finalized_history = update_history(&finalized_history, dapp_address, 5);
+        let tip_history = extend_history(&finalized_history, dapp_address, 1);
+
+        let tip_hash = tip_history.dapp_claims.get(&dapp_address).unwrap().claims[5]
+            .epoch_hash
+            .to_fixed_bytes();
+        let stale_hash = [0xAAu8; 32];
+        assert_ne!(tip_hash, stale_hash);
+
+        {
+            let mut wal = blockchain_driver.wal.lock().unwrap();
+            wal.append(&super::WalEntry {
+                number: 6,
+                hash: stale_hash,
+                submitted_at_block: 1,
+            })
+            .unwrap();
+            wal.append(&super::WalEntry {
+                number: 6,
+                hash: tip_hash,
+                submitted_at_block: 2,
+            })
+            .unwrap();
+        }
+
+        let next_claims = vec![RollupClaim {
+            hash: tip_hash,
+            number: 6,
+        }];
+        let broker = mock::Broker::new(vec![], next_claims);
+        let tx_sender = mock::TxSender::new();
+
+        let result = blockchain_driver
+            .react(3, &tip_history, &finalized_history, &broker, tx_sender)
+            .await;
+        assert!(result.is_ok());
+        // already pending with a matching hash, so it's not resent
+        assert_eq!(result.unwrap().count(), 0);
+
+        let wal = blockchain_driver.wal.lock().unwrap();
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].hash, tip_hash);
+    }
+
+    // once the finalized history catches up to a pending claim's number, the
+    // WAL entry for it is pruned
+    #[tokio::test]
+    async fn test_react_prunes_wal_once_finalized() {
+        let dapp_address = H160::random();
+        let (_dir, blockchain_driver) = new_driver(dapp_address);
+
+        let history = new_history();
+        let history = update_history(&history, dapp_address, 6);
+
+        {
+            let mut wal = blockchain_driver.wal.lock().unwrap();
+            wal.append(&super::WalEntry {
+                number: 6,
+                hash: [1u8; 32],
+                submitted_at_block: 1,
+            })
+            .unwrap();
+        }
+
+        let broker = mock::Broker::new(vec![], vec![]);
+        let tx_sender = mock::TxSender::new();
+
+        let result = blockchain_driver
+            .react(2, &history, &history, &broker, tx_sender)
+            .await;
+        assert!(result.is_ok());
+
+        let wal = blockchain_driver.wal.lock().unwrap();
+        assert!(wal.replay().unwrap().is_empty());
+    }
+}
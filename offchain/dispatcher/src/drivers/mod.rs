@@ -0,0 +1,6 @@
+pub mod blockchain;
+
+#[cfg(test)]
+pub mod mock;
+
+mod wal;
@@ -0,0 +1,226 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A single in-flight claim submission, recorded before the transaction is
+/// broadcast so that it can be reconciled with `History` after a crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub number: u64,
+    pub hash: [u8; 32],
+    /// The tip block at submission time. Kept for diagnostics (e.g. judging
+    /// how long a claim has been pending) -- reconciliation itself compares
+    /// `number`/`hash` against `History`, not this value.
+    pub submitted_at_block: u64,
+}
+
+/// Append-only, file-backed write-ahead log of claim submissions.
+///
+/// Entries are appended (and fsync'd) before `send_claim_tx` is called, so a
+/// crash between broadcast and `History` catching up never loses track of
+/// the attempt. `replay` reconstructs the in-flight set on startup, and
+/// `prune_up_to` drops entries that `History` has since confirmed.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl WriteAheadLog {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)
+            .with_context(|| format!("failed to open WAL file `{}`", path.display()))?;
+        Ok(Self { path, file })
+    }
+
+    /// Appends `entry` to the log and flushes it to disk before returning.
+    pub fn append(&mut self, entry: &WalEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry).context("failed to serialize WAL entry")?;
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .context("failed to append WAL entry")?;
+        self.file.sync_data().context("failed to fsync WAL")?;
+        Ok(())
+    }
+
+    /// Reads back every entry currently in the log, oldest first.
+    pub fn replay(&self) -> Result<Vec<WalEntry>> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("failed to open WAL file `{}`", self.path.display()))?;
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.context("failed to read WAL entry")?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry: WalEntry =
+                serde_json::from_str(&line).context("failed to deserialize WAL entry")?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Drops every entry whose claim `number` is `<= number`, i.e. entries
+    /// `History` has since confirmed, and rewrites the log with the rest.
+    pub fn prune_up_to(&mut self, number: u64) -> Result<()> {
+        self.retain(|entry| entry.number > number)?;
+        Ok(())
+    }
+
+    /// Drops every entry whose `(number, hash)` matches one of `entries`,
+    /// i.e. entries that were reorged out of `History` and must be
+    /// re-evaluated rather than kept around as in-flight. Matching on the
+    /// pair (rather than `number` alone) leaves a second, still-pending
+    /// submission for the same `number` but a different `hash` untouched.
+    pub fn prune_entries(&mut self, entries: &[(u64, [u8; 32])]) -> Result<()> {
+        self.retain(|entry| !entries.contains(&(entry.number, entry.hash)))?;
+        Ok(())
+    }
+
+    /// Replays the log once and keeps only the entries for which `keep`
+    /// returns `true`, returning them. If every entry is kept, this is a
+    /// single read with no rewrite -- callers that run on every `react()`
+    /// tick (not just at startup) rely on this to make the steady-state,
+    /// nothing-to-prune case cheap.
+    pub(crate) fn retain(&mut self, keep: impl Fn(&WalEntry) -> bool) -> Result<Vec<WalEntry>> {
+        let original = self.replay()?;
+        let remaining: Vec<WalEntry> = original
+            .iter()
+            .copied()
+            .filter(|entry| keep(entry))
+            .collect();
+        if remaining.len() == original.len() {
+            return Ok(remaining);
+        }
+
+        let tmp_path = self.path.with_extension("wal.tmp");
+        let mut tmp = File::create(&tmp_path).with_context(|| {
+            format!(
+                "failed to create temporary WAL file `{}`",
+                tmp_path.display()
+            )
+        })?;
+        for entry in &remaining {
+            let mut line = serde_json::to_string(entry).context("failed to serialize WAL entry")?;
+            line.push('\n');
+            tmp.write_all(line.as_bytes())
+                .context("failed to write temporary WAL file")?;
+        }
+        tmp.sync_all()
+            .context("failed to fsync temporary WAL file")?;
+        drop(tmp);
+
+        std::fs::rename(&tmp_path, &self.path)
+            .context("failed to replace WAL file with pruned copy")?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to reopen WAL file `{}`", self.path.display()))?;
+
+        Ok(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WalEntry, WriteAheadLog};
+
+    fn entry(number: u64, submitted_at_block: u64) -> WalEntry {
+        WalEntry {
+            number,
+            hash: [number as u8; 32],
+            submitted_at_block,
+        }
+    }
+
+    #[test]
+    fn test_append_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WriteAheadLog::open(dir.path().join("wal.log")).unwrap();
+
+        wal.append(&entry(1, 10)).unwrap();
+        wal.append(&entry(2, 11)).unwrap();
+
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries, vec![entry(1, 10), entry(2, 11)]);
+    }
+
+    #[test]
+    fn test_prune_up_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WriteAheadLog::open(dir.path().join("wal.log")).unwrap();
+
+        wal.append(&entry(1, 10)).unwrap();
+        wal.append(&entry(2, 11)).unwrap();
+        wal.append(&entry(3, 12)).unwrap();
+
+        wal.prune_up_to(2).unwrap();
+
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries, vec![entry(3, 12)]);
+    }
+
+    #[test]
+    fn test_prune_up_to_no_op_keeps_entries_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WriteAheadLog::open(dir.path().join("wal.log")).unwrap();
+
+        wal.append(&entry(1, 10)).unwrap();
+        wal.append(&entry(2, 11)).unwrap();
+
+        // nothing is at or below 0, so this is a no-op and must not drop
+        // or reorder any entry
+        wal.prune_up_to(0).unwrap();
+
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries, vec![entry(1, 10), entry(2, 11)]);
+    }
+
+    #[test]
+    fn test_prune_entries_only_matches_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WriteAheadLog::open(dir.path().join("wal.log")).unwrap();
+
+        let stale = WalEntry {
+            number: 6,
+            hash: [0xA; 32],
+            submitted_at_block: 10,
+        };
+        let pending = WalEntry {
+            number: 6,
+            hash: [0xB; 32],
+            submitted_at_block: 11,
+        };
+        wal.append(&stale).unwrap();
+        wal.append(&pending).unwrap();
+
+        wal.prune_entries(&[(stale.number, stale.hash)]).unwrap();
+
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries, vec![pending]);
+    }
+
+    #[test]
+    fn test_replay_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut wal = WriteAheadLog::open(&path).unwrap();
+        wal.append(&entry(1, 10)).unwrap();
+        drop(wal);
+
+        let wal = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(wal.replay().unwrap(), vec![entry(1, 10)]);
+    }
+}